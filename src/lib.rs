@@ -1,15 +1,18 @@
-use std::cell::RefCell;
-use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
+use std::hash::{BuildHasher, BuildHasherDefault, Hash, Hasher};
 use std::io::Cursor;
-use std::rc::Rc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 
 use blake2::{Blake2b, Digest};
 use byteorder::{LittleEndian, ReadBytesExt};
 
 #[derive(Debug)]
-pub struct Host {
-    name: String,
-    load: u64,
+pub struct Host<K> {
+    name: K,
+    load: AtomicU64,
+    weight: u32,
 }
 
 #[derive(Debug)]
@@ -27,26 +30,140 @@ impl Default for Config {
     }
 }
 
-pub struct Ring {
-    config: Config,
+/// Per-host row of a [`Stats`] report.
+#[derive(Debug)]
+pub struct HostStats<K> {
+    pub name: K,
+    /// Fraction of the hash space this host owns, derived from the gaps
+    /// between its virtual nodes' neighbours on the ring.
+    pub share: f64,
+    pub load: u64,
+}
+
+/// A snapshot of how evenly a ring's keys and load are distributed across
+/// its hosts, for spotting a `replication_factor` that is too low or
+/// bounded-load bouncing that is concentrating traffic on a few hosts.
+#[derive(Debug)]
+pub struct Stats<K> {
+    pub hosts: Vec<HostStats<K>>,
+    pub total_load: u64,
+    /// Ratio of the most-loaded host's load to the mean load across hosts.
+    /// Close to 1.0 means load is spread evenly; much higher points at a
+    /// hot host.
+    pub max_mean_load_ratio: f64,
+    /// Standard deviation of per-host hash-space share. Close to 0 means
+    /// every host owns roughly an equal slice of the ring.
+    pub share_stddev: f64,
+}
+
+/// A [`BuildHasher`] that reproduces the crate's original Blake2b-based
+/// hashing. Use this when you need rings in different processes to agree
+/// on key placement exactly as earlier versions of this crate did.
+///
+/// [`Blake2bHasher`] writes `str` keys and `u64` virtual-node indices the
+/// same way the pre-generic-key implementation did (raw bytes, decimal
+/// digits, no framing), so hashing a string key or a `"{hostname}{i}"`
+/// virtual node through it reproduces the original digest byte-for-byte.
+/// Every other value that reaches it through [`Hash`] (`u8`, `i8`, `bool`,
+/// or a composite key containing one) is still hashed, just not via a
+/// legacy format this crate ever produced — only a `0xff` passed to
+/// `write_u8` directly after a raw `write`, which is where `str`'s `Hash`
+/// impl puts its sentinel after a key's bytes, is dropped, so the sentinel
+/// doesn't get folded into the digest as stray input. A `0xff` `u8` that
+/// directly follows a byte slice or a `u16`/`u32`/`usize` is dropped the
+/// same way.
+#[derive(Clone, Default)]
+pub struct Blake2bHash;
+
+impl BuildHasher for Blake2bHash {
+    type Hasher = Blake2bHasher;
+
+    fn build_hasher(&self) -> Self::Hasher {
+        Blake2bHasher::default()
+    }
+}
 
-    hashes: Vec<u64>,                                 // hashes sorted ascendingly
-    host_by_hash: HashMap<u64, Rc<RefCell<Host>>>,    // index host by hash
-    host_by_name: HashMap<String, Rc<RefCell<Host>>>, // index host by name
-    load: u64,                                        // the total load of ring
+pub struct Blake2bHasher {
+    state: Blake2b,
+    // Set right after a raw `write` (the default `write_u16`/`write_u32`/
+    // `write_usize` go through it too), cleared by `write_u8` and `write_u64`.
+    // `str`'s `Hash` impl is the only caller in this crate's key types that
+    // follows a `write` with `write_u8(0xff)`, so this lets us recognize
+    // that exact sentinel and drop it without guessing at every `write_u8`
+    // call, which used to swallow real `u8`/`bool` values too.
+    after_raw_write: bool,
 }
 
-unsafe impl Send for Ring {}
-unsafe impl Sync for Ring {}
+impl Default for Blake2bHasher {
+    fn default() -> Self {
+        Blake2bHasher {
+            state: Blake2b::new(),
+            after_raw_write: false,
+        }
+    }
+}
 
-impl Ring {
+impl Hasher for Blake2bHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        self.state.input(bytes);
+        self.after_raw_write = true;
+    }
+
+    fn write_u8(&mut self, i: u8) {
+        if self.after_raw_write && i == 0xff {
+            // The sentinel `str`'s `Hash` impl appends after a key's bytes;
+            // drop it so hashing a string key reproduces the original
+            // `hasher.write(key.as_bytes())` call byte-for-byte.
+            self.after_raw_write = false;
+            return;
+        }
+        self.state.input(&[i]);
+        self.after_raw_write = false;
+    }
+
+    fn write_u64(&mut self, i: u64) {
+        // Virtual-node hashing used to feed `format!("{hostname}{i}")`, i.e.
+        // the hostname bytes followed by `i`'s decimal digits. Write the
+        // digits here so `hash_node`'s `(hostname, i)` tuple reproduces that
+        // same byte stream.
+        self.state.input(i.to_string().as_bytes());
+        self.after_raw_write = false;
+    }
+
+    fn finish(&self) -> u64 {
+        let hash = self.state.clone().result();
+        Cursor::new(hash).read_u64::<LittleEndian>().unwrap()
+    }
+}
+
+pub struct Ring<K, S = BuildHasherDefault<DefaultHasher>> {
+    config: Config,
+    hasher: S,
+
+    hashes: Vec<u64>,                         // hashes sorted ascendingly
+    host_by_hash: HashMap<u64, Arc<Host<K>>>, // index host by hash
+    host_by_name: HashMap<K, Arc<Host<K>>>,   // index host by name
+    load: AtomicU64,                          // the total load of ring
+}
+
+impl<K: Eq + Hash + Clone> Ring<K, BuildHasherDefault<DefaultHasher>> {
     pub fn new(config: Config) -> Self {
+        Self::with_hasher(config, Default::default())
+    }
+}
+
+impl<K: Eq + Hash + Clone, S: BuildHasher> Ring<K, S> {
+    /// Builds a ring that hashes keys with a caller-supplied [`BuildHasher`],
+    /// e.g. [`Blake2bHash`] for the crate's original hashing, or any other
+    /// `std::hash::BuildHasher` such as a crypto digest or xxHash.
+    pub fn with_hasher(config: Config, hasher: S) -> Self {
         Self {
-            config: config,
+            config,
+            hasher,
             hashes: Default::default(),
             host_by_hash: Default::default(),
             host_by_name: Default::default(),
-            load: 0,
+            load: AtomicU64::new(0),
         }
     }
 
@@ -54,22 +171,30 @@ impl Ring {
         self.config.replication_factor
     }
 
-    /// Adds a new host to the ring.
+    /// Adds a new host to the ring with weight 1.
     /// If the host already added, ignore.
-    pub fn add(&mut self, hostname: &str) {
-        if self.host_by_name.contains_key(hostname) {
+    pub fn add(&mut self, hostname: K) {
+        self.add_weighted(hostname, 1);
+    }
+
+    /// Adds a new host to the ring, placing `weight * replication_factor`
+    /// virtual nodes for it so it absorbs a proportional share of keys.
+    /// If the host already added, ignore.
+    pub fn add_weighted(&mut self, hostname: K, weight: u32) {
+        if self.host_by_name.contains_key(&hostname) {
             return;
         }
 
-        let host = Rc::new(RefCell::new(Host {
-            name: hostname.to_owned(),
-            load: 0,
-        }));
+        let host = Arc::new(Host {
+            name: hostname.clone(),
+            load: AtomicU64::new(0),
+            weight,
+        });
 
-        self.host_by_name.insert(hostname.to_owned(), host.clone());
+        self.host_by_name.insert(hostname.clone(), host.clone());
 
-        for i in 0..self.replication_factor() {
-            let hash = Self::hash(&format!("{}{}", hostname, i));
+        for i in 0..self.points(weight) {
+            let hash = self.hash_node(&hostname, i);
             self.host_by_hash.insert(hash, host.clone());
             self.hashes.push(hash);
         }
@@ -78,9 +203,18 @@ impl Ring {
     }
 
     /// Removes host from the ring.
-    pub fn remove(&mut self, hostname: &str) {
-        for i in 0..self.replication_factor() {
-            let hash = Self::hash(&format!("{}{}", hostname, i));
+    pub fn remove<Q>(&mut self, hostname: &Q)
+    where
+        K: std::borrow::Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let weight = match self.host_by_name.get(hostname) {
+            Some(host) => host.weight,
+            None => return,
+        };
+
+        for i in 0..self.points(weight) {
+            let hash = self.hash_node(hostname, i);
             self.host_by_hash.remove(&hash);
             let idx = self.hashes.iter().position(|x| *x == hash).unwrap();
             self.hashes.remove(idx);
@@ -90,34 +224,32 @@ impl Ring {
     }
 
     /// Locates a host for the key.
-    pub fn get(&mut self, key: &str) -> Option<String> {
+    pub fn get<Q: Hash>(&self, key: Q) -> Option<K> {
         if self.host_by_hash.is_empty() {
             return None;
         }
 
-        let hash = Self::hash(key);
+        let hash = self.hash(key);
         let idx = self.search(hash);
-        if let Some(host) = self.host_by_hash.get(&self.hashes[idx]) {
-            Some(host.borrow().name.clone())
-        } else {
-            None
-        }
+        self.host_by_hash
+            .get(&self.hashes[idx])
+            .map(|host| host.name.clone())
     }
 
     /// Picks the least load host for the key.
-    pub fn get_least(&mut self, key: &str) -> Option<String> {
+    pub fn get_least<Q: Hash>(&self, key: Q) -> Option<K> {
         if self.host_by_hash.is_empty() {
             return None;
         }
 
-        let hash = Self::hash(key);
+        let hash = self.hash(key);
         let avg_load = self.avg_load();
 
         let mut idx = self.search(hash);
         loop {
             let host = self.host_by_hash.get(&self.hashes[idx]).unwrap();
-            if (host.borrow().load + 1) as f64 <= avg_load {
-                return Some(host.borrow().name.clone());
+            if (host.load.load(Ordering::Relaxed) + 1) as f64 <= host.weight as f64 * avg_load {
+                return Some(host.name.clone());
             }
             idx += 1;
             if idx >= self.host_by_hash.len() {
@@ -126,46 +258,181 @@ impl Ring {
         }
     }
 
+    /// Walks clockwise from the key's position collecting up to `n` distinct
+    /// physical hosts, for picking a primary plus replicas. Virtual nodes
+    /// that resolve to an already-chosen host are skipped.
+    pub fn get_n<Q: Hash>(&self, key: Q, n: usize) -> Vec<K> {
+        let hash = self.hash(key);
+        self.collect_n(hash, n, |_| true)
+    }
+
+    /// Like [`Ring::get_n`], but only accepts a host into the replica set if
+    /// it is under its weighted share of the ring's average load.
+    pub fn get_least_n<Q: Hash>(&self, key: Q, n: usize) -> Vec<K> {
+        let avg_load = self.avg_load();
+        let hash = self.hash(key);
+        self.collect_n(hash, n, |host| {
+            (host.load.load(Ordering::Relaxed) + 1) as f64 <= host.weight as f64 * avg_load
+        })
+    }
+
+    /// Shared walk for [`Ring::get_n`] and [`Ring::get_least_n`]: starts at
+    /// `hash`'s position and collects distinct hosts (by name) accepted by
+    /// `accept`, stopping at `n` hosts or once every host has been visited.
+    fn collect_n(&self, hash: u64, n: usize, accept: impl Fn(&Host<K>) -> bool) -> Vec<K> {
+        if self.host_by_hash.is_empty() || n == 0 {
+            return Vec::new();
+        }
+
+        let mut seen = HashSet::new();
+        let mut result = Vec::new();
+        let mut idx = self.search(hash);
+
+        for _ in 0..self.hashes.len() {
+            if result.len() >= n || seen.len() >= self.host_by_name.len() {
+                break;
+            }
+
+            let host = self.host_by_hash.get(&self.hashes[idx]).unwrap();
+            if seen.insert(host.name.clone()) && accept(host) {
+                result.push(host.name.clone());
+            }
+
+            idx += 1;
+            if idx >= self.hashes.len() {
+                idx = 0;
+            }
+        }
+
+        result
+    }
+
     /// Lists all hosts in the ring.
-    pub fn hosts(&mut self) -> Vec<String> {
-        self.host_by_name.keys().cloned().into_iter().collect()
+    pub fn hosts(&self) -> Vec<K> {
+        self.host_by_name.keys().cloned().collect()
     }
 
     /// Sets the load of host to the given value.
-    pub fn set_load(&mut self, hostname: &str, load: u64) {
+    pub fn set_load<Q>(&self, hostname: &Q, load: u64)
+    where
+        K: std::borrow::Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
         if let Some(host) = self.host_by_name.get(hostname) {
-            let mut host = host.borrow_mut();
-            self.load -= host.load;
-            host.load = load;
-            self.load += load;
+            let old = host.load.swap(load, Ordering::Relaxed);
+            self.load
+                .fetch_add(load.wrapping_sub(old), Ordering::Relaxed);
         }
     }
 
     /// Increments the load of host by 1.
-    pub fn inc_load(&mut self, hostname: &str) {
+    pub fn inc_load<Q>(&self, hostname: &Q)
+    where
+        K: std::borrow::Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
         if let Some(host) = self.host_by_name.get(hostname) {
-            self.load += 1;
-            host.borrow_mut().load += 1;
+            host.load.fetch_add(1, Ordering::Relaxed);
+            self.load.fetch_add(1, Ordering::Relaxed);
         }
     }
 
     /// Decrements the load of host by 1.
-    pub fn decr_load(&mut self, hostname: &str) {
+    pub fn decr_load<Q>(&self, hostname: &Q)
+    where
+        K: std::borrow::Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
         if let Some(host) = self.host_by_name.get(hostname) {
-            self.load -= 1;
-            host.borrow_mut().load -= 1;
+            host.load.fetch_sub(1, Ordering::Relaxed);
+            self.load.fetch_sub(1, Ordering::Relaxed);
         }
     }
 
     /// Gets the average load of ring.
     pub fn avg_load(&self) -> f64 {
-        let mut load = (self.load + 1) as f64 / self.host_by_name.len() as f64;
+        let total_load = self.load.load(Ordering::Relaxed);
+        let mut load = (total_load + 1) as f64 / self.host_by_name.len() as f64;
         if load == 0.0 {
             load = 1.0;
         }
         (load * self.config.load).ceil()
     }
 
+    /// Reports per-host hash-space ownership and load, and derived balance
+    /// metrics, for judging how evenly this ring's configuration spreads
+    /// keys and traffic across its hosts.
+    pub fn stats(&self) -> Stats<K> {
+        let total_hosts = self.host_by_name.len();
+        let total_load = self.load.load(Ordering::Relaxed);
+        if total_hosts == 0 {
+            return Stats {
+                hosts: Vec::new(),
+                total_load,
+                max_mean_load_ratio: 0.0,
+                share_stddev: 0.0,
+            };
+        }
+
+        const HASH_SPACE: f64 = 18_446_744_073_709_551_616.0; // 2^64
+
+        let n = self.hashes.len();
+        let mut share_by_name: HashMap<K, f64> = HashMap::new();
+        for i in 0..n {
+            let prev = if i == 0 {
+                self.hashes[n - 1]
+            } else {
+                self.hashes[i - 1]
+            };
+            // A lone virtual node's arc runs all the way round to itself.
+            let gap = if n == 1 {
+                HASH_SPACE
+            } else {
+                self.hashes[i].wrapping_sub(prev) as f64
+            };
+            let host = self.host_by_hash.get(&self.hashes[i]).unwrap();
+            *share_by_name.entry(host.name.clone()).or_insert(0.0) += gap / HASH_SPACE;
+        }
+
+        let mean_share = 1.0 / total_hosts as f64;
+        let mean_load = total_load as f64 / total_hosts as f64;
+
+        let hosts: Vec<HostStats<K>> = self
+            .host_by_name
+            .values()
+            .map(|host| HostStats {
+                name: host.name.clone(),
+                share: *share_by_name.get(&host.name).unwrap_or(&0.0),
+                load: host.load.load(Ordering::Relaxed),
+            })
+            .collect();
+
+        let share_variance = hosts
+            .iter()
+            .map(|h| (h.share - mean_share).powi(2))
+            .sum::<f64>()
+            / total_hosts as f64;
+
+        let max_load = hosts.iter().map(|h| h.load).max().unwrap_or(0);
+        let max_mean_load_ratio = if mean_load > 0.0 {
+            max_load as f64 / mean_load
+        } else {
+            0.0
+        };
+
+        Stats {
+            hosts,
+            total_load,
+            max_mean_load_ratio,
+            share_stddev: share_variance.sqrt(),
+        }
+    }
+
+    /// Number of virtual nodes a host of the given weight places on the ring.
+    fn points(&self, weight: u32) -> u64 {
+        weight as u64 * self.replication_factor()
+    }
+
     fn search(&self, key: u64) -> usize {
         for i in 0..self.hashes.len() {
             let idx = self.hashes[i];
@@ -177,14 +444,15 @@ impl Ring {
         0
     }
 
-    /// Hashes key.
-    /// TODO(luncj): supports custom hasher.
-    fn hash(key: &str) -> u64 {
-        let hash = Blake2b::new().chain(key.as_bytes()).result();
-
-        let mut rdr = Cursor::new(hash);
+    /// Hashes an arbitrary key through the configured `BuildHasher`.
+    fn hash<T: Hash>(&self, value: T) -> u64 {
+        self.hasher.hash_one(value)
+    }
 
-        rdr.read_u64::<LittleEndian>().unwrap()
+    /// Hashes the i-th virtual node of a host, without ever materializing a
+    /// formatted "{hostname}{i}" string.
+    fn hash_node<Q: Hash + ?Sized>(&self, hostname: &Q, i: u64) -> u64 {
+        self.hasher.hash_one((hostname, i))
     }
 }
 
@@ -219,4 +487,184 @@ mod tests {
         assert!(r.hashes.is_empty());
         assert!(r.hosts().is_empty());
     }
+
+    #[test]
+    fn ring_with_blake2b_hash() {
+        use super::Blake2bHash;
+        use crate::Config;
+
+        let mut r = Ring::with_hasher(Config::default(), Blake2bHash);
+        r.add("1.1.1.1");
+        let host = r.get("1.1.1.1");
+
+        assert!(host.is_some());
+        assert_eq!("1.1.1.1", host.unwrap());
+    }
+
+    #[test]
+    fn ring_blake2b_hash_matches_legacy_output() {
+        use super::Blake2bHash;
+        use std::hash::BuildHasher;
+
+        // Literal digests produced by the crate's original, pre-generic-key
+        // Blake2b hashing (`Blake2b::new().chain(key.as_bytes()).result()`
+        // read back as a little-endian u64). `Blake2bHash` exists so
+        // upgrading doesn't silently reshuffle a ring's key placement, so
+        // pin its output against these known-good values.
+        assert_eq!(
+            Blake2bHash.hash_one("1.1.1.1"),
+            5736197838338862417,
+            "key hash for 1.1.1.1 diverged from the legacy Blake2b digest"
+        );
+        assert_eq!(
+            Blake2bHash.hash_one("2.2.2.2"),
+            8232780361711054611,
+            "key hash for 2.2.2.2 diverged from the legacy Blake2b digest"
+        );
+
+        let legacy_node_hashes = [
+            (0u64, 805149492728074093u64),
+            (1u64, 6921120840966506703u64),
+            (2u64, 5563810853876459312u64),
+        ];
+        for (i, expected) in legacy_node_hashes {
+            assert_eq!(
+                Blake2bHash.hash_one(("1.1.1.1", i)),
+                expected,
+                "virtual-node hash for 1.1.1.1/{i} diverged from the legacy Blake2b digest"
+            );
+        }
+    }
+
+    #[test]
+    fn ring_blake2b_hash_does_not_collide_on_raw_bytes() {
+        use super::Blake2bHash;
+        use std::hash::BuildHasher;
+
+        assert_ne!(Blake2bHash.hash_one(true), Blake2bHash.hash_one(false));
+        assert_ne!(Blake2bHash.hash_one(5u8), Blake2bHash.hash_one(7u8));
+        assert_ne!(
+            Blake2bHash.hash_one(("host", 5u8)),
+            Blake2bHash.hash_one(("host", 9u8))
+        );
+    }
+
+    #[test]
+    fn ring_add_weighted() {
+        let mut r = Ring::new(Default::default());
+        r.add_weighted("1.1.1.1", 3);
+
+        assert_eq!(r.replication_factor() * 3, r.hashes.len() as u64);
+
+        r.remove("1.1.1.1");
+        assert!(r.hashes.is_empty());
+    }
+
+    #[test]
+    fn ring_non_string_key() {
+        let mut r: Ring<u64> = Ring::new(Default::default());
+        r.add(1);
+        r.add(2);
+        r.add(3);
+
+        let host = r.get("some-shard");
+        assert!(host.is_some());
+    }
+
+    #[test]
+    fn ring_get_n_returns_distinct_hosts() {
+        let mut r = Ring::new(Default::default());
+        r.add("1.1.1.1");
+        r.add("2.2.2.2");
+        r.add("3.3.3.3");
+
+        let mut hosts = r.get_n("some-key", 2);
+        hosts.sort();
+
+        assert_eq!(2, hosts.len());
+        hosts.dedup();
+        assert_eq!(2, hosts.len());
+    }
+
+    #[test]
+    fn ring_get_n_caps_at_host_count() {
+        let mut r = Ring::new(Default::default());
+        r.add("1.1.1.1");
+        r.add("2.2.2.2");
+
+        assert_eq!(2, r.get_n("some-key", 5).len());
+    }
+
+    #[test]
+    fn ring_get_least_n_skips_overloaded_hosts() {
+        let mut r = Ring::new(Default::default());
+        r.add("1.1.1.1");
+        r.add("2.2.2.2");
+        r.add("3.3.3.3");
+
+        for _ in 0..100 {
+            r.inc_load("1.1.1.1");
+        }
+
+        let hosts = r.get_least_n("some-key", 2);
+        assert_eq!(2, hosts.len());
+        assert!(!hosts.contains(&"1.1.1.1"));
+    }
+
+    #[test]
+    fn ring_stats_shares_sum_to_one() {
+        let mut r = Ring::new(Default::default());
+        r.add("1.1.1.1");
+        r.add("2.2.2.2");
+        r.add("3.3.3.3");
+        r.inc_load("1.1.1.1");
+        r.inc_load("2.2.2.2");
+
+        let stats = r.stats();
+
+        assert_eq!(3, stats.hosts.len());
+        assert_eq!(2, stats.total_load);
+
+        let total_share: f64 = stats.hosts.iter().map(|h| h.share).sum();
+        assert!((total_share - 1.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn ring_stats_empty() {
+        let r: Ring<&str> = Ring::new(Default::default());
+        let stats = r.stats();
+
+        assert!(stats.hosts.is_empty());
+        assert_eq!(0.0, stats.max_mean_load_ratio);
+    }
+
+    #[test]
+    fn ring_shared_across_threads() {
+        use std::sync::Arc;
+        use std::thread;
+
+        let mut r = Ring::new(Default::default());
+        r.add("1.1.1.1");
+        r.add("2.2.2.2");
+        r.add("3.3.3.3");
+        let r = Arc::new(r);
+
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                let r = r.clone();
+                thread::spawn(move || {
+                    for _ in 0..100 {
+                        r.inc_load("1.1.1.1");
+                        r.get_least("some-key");
+                    }
+                })
+            })
+            .collect();
+
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        assert_eq!(800, r.stats().total_load);
+    }
 }